@@ -1,8 +1,80 @@
 pub use tokio_trace_core::subscriber::*;
 
-use std::{cell::RefCell, default::Default, thread};
+use std::{
+    cell::RefCell,
+    default::Default,
+    panic::{catch_unwind, resume_unwind, AssertUnwindSafe},
+    thread,
+};
+use futures::{Future, Poll};
 use Id;
 
+/// Tracks the span in which the current task, thread, or other unit of
+/// execution is executing.
+///
+/// This trait exists so that a `Subscriber` can be written generically over
+/// how that tracking is actually implemented. `CurrentSpanPerThread`, below,
+/// is the default implementation, which stores the span stack in a
+/// thread-local. A runtime that schedules tasks onto multiple OS threads
+/// (where a thread-local would not follow a task that migrates between
+/// threads) can instead supply an implementation that stores the stack in
+/// its own task-local storage, and a single-threaded or `no_std` target can
+/// supply a zero-cost implementation backed by a global `static`.
+pub trait CurrentSpan {
+    /// Returns the [`Id`](::Id) of the span in which the current thread is
+    /// executing, or `None` if it is not inside of a span.
+    fn id(&self) -> Option<Id>;
+
+    /// Records that the current thread has entered the span with the given
+    /// `Id`.
+    fn enter(&self, span: Id);
+
+    /// Records that the current thread has exited the span with the given
+    /// `Id`.
+    ///
+    /// Usually, `span` is the span which was most recently entered. However,
+    /// spans are not required to exit in the same order they were entered in
+    /// --- a span entered and exited from a callback or a poll loop may close
+    /// out of order, in which case `span` is removed from wherever it is in
+    /// the stack, without disturbing the spans above or below it.
+    fn exit(&self, span: &Id);
+
+    /// Enters the span with the given `Id`, returning a RAII guard that will
+    /// exit the span when dropped.
+    ///
+    /// This is preferable to calling [`enter`](CurrentSpan::enter) and
+    /// [`exit`](CurrentSpan::exit) directly, since it guarantees that the two
+    /// calls stay balanced even when entry and exit can't be lexically
+    /// scoped, e.g. across callbacks or poll loops.
+    fn enter_scoped(&self, span: Id) -> Entered<Self>
+    where
+        Self: Sized,
+    {
+        let guard_span = span.clone();
+        self.enter(span);
+        Entered {
+            current: self,
+            span: guard_span,
+        }
+    }
+}
+
+/// An RAII guard representing a span which has been entered and is
+/// currently executing.
+///
+/// When the guard is dropped, the span will be exited.
+#[derive(Debug)]
+pub struct Entered<'a, C: CurrentSpan + ?Sized + 'a> {
+    current: &'a C,
+    span: Id,
+}
+
+impl<'a, C: CurrentSpan + ?Sized> Drop for Entered<'a, C> {
+    fn drop(&mut self) {
+        self.current.exit(&self.span);
+    }
+}
+
 /// Tracks the currently executing span on a per-thread basis.
 ///
 /// This is intended for use by `Subscriber` implementations.
@@ -15,23 +87,36 @@ impl CurrentSpanPerThread {
     pub fn new() -> Self {
         Self::default()
     }
+}
 
-    /// Returns the [`Id`](::Id) of the span in which the current thread is
-    /// executing, or `None` if it is not inside of a span.
-    pub fn id(&self) -> Option<Id> {
+impl CurrentSpan for CurrentSpanPerThread {
+    fn id(&self) -> Option<Id> {
         self.current
             .with(|current| current.borrow().last().cloned())
     }
 
-    pub fn enter(&self, span: Id) {
+    fn enter(&self, span: Id) {
         self.current.with(|current| {
             current.borrow_mut().push(span);
         })
     }
 
-    pub fn exit(&self) {
+    fn exit(&self, span: &Id) {
         self.current.with(|current| {
-            let _ = current.borrow_mut().pop();
+            let mut current = current.borrow_mut();
+            // Find `span` starting from the top of the stack: if it has been
+            // entered more than once, the most recently entered occurrence is
+            // the one being exited. This also handles the out-of-order case,
+            // removing `span` from the middle of the stack without
+            // disturbing the spans above or below it.
+            let position = current.iter().rposition(|id| id == span);
+            debug_assert!(
+                position.is_some(),
+                "tried to exit a span that was never entered (or was already exited)"
+            );
+            if let Some(position) = position {
+                current.remove(position);
+            }
         })
     }
 }
@@ -45,6 +130,76 @@ impl Default for CurrentSpanPerThread {
     }
 }
 
+/// A future that has been instrumented with a `Span`.
+///
+/// Unlike entering the span around a synchronous block of code, a future may
+/// be polled on a different thread each time, or may be suspended and resumed
+/// across many `await` points with other futures interleaved in between. The
+/// thread-local current-span stack has no way of knowing that; it would
+/// happily leave a stale `Id` behind on a thread the task no longer owns. To
+/// avoid that, `Instrumented` re-enters its span on every `poll` and exits it
+/// again before returning, rather than relying on the span remaining entered
+/// across the `poll` call.
+///
+/// `Instrumented` is generic over the [`CurrentSpan`] backend it re-enters
+/// the span on, defaulting to `CurrentSpanPerThread`. A runtime that needs
+/// task-local (rather than thread-local) span tracking can supply its own
+/// `CurrentSpan` implementation via [`Instrument::instrument_with`].
+#[derive(Debug)]
+pub struct Instrumented<T, C = CurrentSpanPerThread> {
+    inner: T,
+    span: Id,
+    current: C,
+}
+
+impl<T, C> Future for Instrumented<T, C>
+where
+    T: Future,
+    C: CurrentSpan,
+{
+    type Item = T::Item;
+    type Error = T::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.current.enter(self.span.clone());
+        // The inner future is polled while the span is entered, but we must
+        // still exit the span if the poll panics --- otherwise, the panicking
+        // thread is left with a dangling `Id` on its current-span stack.
+        let result = catch_unwind(AssertUnwindSafe(|| self.inner.poll()));
+        self.current.exit(&self.span);
+        match result {
+            Ok(poll) => poll,
+            Err(panic) => resume_unwind(panic),
+        }
+    }
+}
+
+/// Extension trait allowing futures to be instrumented with a `Span`.
+pub trait Instrument: Sized {
+    /// Instruments this future with the span with the provided `Id`, returning
+    /// an `Instrumented` future that re-enters the span every time it is
+    /// polled, using the default per-thread `CurrentSpan` backend.
+    fn instrument(self, span: Id) -> Instrumented<Self> {
+        self.instrument_with(span, CurrentSpanPerThread::new())
+    }
+
+    /// Instruments this future with the span with the provided `Id`, re-entering it on
+    /// the given [`CurrentSpan`] backend every time the future is polled.
+    ///
+    /// This is the hook a host runtime uses to plug in its own span-tracking
+    /// strategy --- for example, a task-local implementation that follows a
+    /// task as it migrates between worker threads.
+    fn instrument_with<C: CurrentSpan>(self, span: Id, current: C) -> Instrumented<Self, C> {
+        Instrumented {
+            inner: self,
+            span,
+            current,
+        }
+    }
+}
+
+impl<T: Future> Instrument for T {}
+
 /// Sets this dispatch as the default for the duration of a closure.
 ///
 /// The default dispatcher is used when creating a new [`Span`] or
@@ -64,11 +219,200 @@ where
 
 #[cfg(test)]
 mod tests {
+    use std::panic;
     use std::sync::{
         atomic::{AtomicUsize, Ordering},
-        Arc,
+        Arc, Mutex,
     };
-    use {dispatcher, span, subscriber, Dispatch};
+    use futures::{Async, Future, Poll};
+    use {dispatcher, span, subscriber, Dispatch, Id};
+
+    use super::{CurrentSpan, CurrentSpanPerThread, Instrument};
+
+    /// A `CurrentSpan` backend storing the span stack behind a mutex,
+    /// standing in for a task-local implementation supplied by a host
+    /// runtime rather than the default thread-local one.
+    #[derive(Clone, Default)]
+    struct MockCurrentSpan(Arc<Mutex<Vec<Id>>>);
+
+    impl CurrentSpan for MockCurrentSpan {
+        fn id(&self) -> Option<Id> {
+            self.0.lock().unwrap().last().cloned()
+        }
+
+        fn enter(&self, span: Id) {
+            self.0.lock().unwrap().push(span);
+        }
+
+        fn exit(&self, span: &Id) {
+            let mut stack = self.0.lock().unwrap();
+            if let Some(position) = stack.iter().rposition(|id| id == span) {
+                stack.remove(position);
+            }
+        }
+    }
+
+    #[test]
+    fn instrumented_future_exits_span_when_inner_poll_panics() {
+        struct PanicsOnPoll;
+        impl Future for PanicsOnPoll {
+            type Item = ();
+            type Error = ();
+
+            fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+                panic!("the future panicked");
+            }
+        }
+
+        let current = CurrentSpanPerThread::new();
+        let span = Id::from_u64(1);
+        let mut future = PanicsOnPoll.instrument(span);
+
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| future.poll()));
+        assert!(result.is_err(), "the panic should propagate");
+        assert_eq!(
+            current.id(),
+            None,
+            "the span should have been exited even though the inner poll panicked"
+        );
+    }
+
+    #[test]
+    fn instrumented_future_exits_span_when_inner_poll_is_not_ready() {
+        struct Stalls;
+        impl Future for Stalls {
+            type Item = ();
+            type Error = ();
+
+            fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+                Ok(Async::NotReady)
+            }
+        }
+
+        let current = CurrentSpanPerThread::new();
+        let span = Id::from_u64(2);
+        let mut future = Stalls.instrument(span);
+
+        assert_eq!(future.poll(), Ok(Async::NotReady));
+        assert_eq!(
+            current.id(),
+            None,
+            "the span should have been exited after a Pending poll"
+        );
+    }
+
+    #[test]
+    fn instrument_with_uses_the_provided_current_span_backend() {
+        struct AssertsSpanIsEntered {
+            current: MockCurrentSpan,
+            span: Id,
+        }
+
+        impl Future for AssertsSpanIsEntered {
+            type Item = ();
+            type Error = ();
+
+            fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+                assert_eq!(
+                    self.current.id(),
+                    Some(self.span.clone()),
+                    "the custom CurrentSpan backend should have the span entered while polling"
+                );
+                Ok(Async::Ready(()))
+            }
+        }
+
+        let current = MockCurrentSpan::default();
+        let span = Id::from_u64(30);
+        let inner = AssertsSpanIsEntered {
+            current: current.clone(),
+            span: span.clone(),
+        };
+
+        let mut future = inner.instrument_with(span, current.clone());
+
+        // The default thread-local backend must never see this span; it was
+        // never told about it.
+        let per_thread = CurrentSpanPerThread::new();
+
+        assert_eq!(future.poll(), Ok(Async::Ready(())));
+        assert_eq!(
+            per_thread.id(),
+            None,
+            "instrument_with should not touch the thread-local CurrentSpan backend"
+        );
+        assert_eq!(
+            current.id(),
+            None,
+            "the custom backend's span should have been exited once the poll returned"
+        );
+    }
+
+    #[test]
+    fn exit_removes_out_of_order_id_from_middle_of_stack() {
+        let current = CurrentSpanPerThread::new();
+        let outer = Id::from_u64(10);
+        let middle = Id::from_u64(11);
+        let inner = Id::from_u64(12);
+
+        current.enter(outer.clone());
+        current.enter(middle.clone());
+        current.enter(inner.clone());
+
+        // Exit "middle" out of order; "inner", the actual top of the stack,
+        // should be untouched.
+        current.exit(&middle);
+        assert_eq!(
+            current.id(),
+            Some(inner.clone()),
+            "the top of the stack should be unaffected by removing a span from the middle"
+        );
+
+        current.exit(&inner);
+        assert_eq!(
+            current.id(),
+            Some(outer.clone()),
+            "removing \"middle\" should not have disturbed \"outer\""
+        );
+
+        current.exit(&outer);
+        assert_eq!(current.id(), None);
+    }
+
+    #[test]
+    fn entered_guard_exits_span_on_drop() {
+        let current = CurrentSpanPerThread::new();
+        let span = Id::from_u64(20);
+
+        {
+            let _guard = current.enter_scoped(span.clone());
+            assert_eq!(current.id(), Some(span.clone()));
+        }
+
+        assert_eq!(
+            current.id(),
+            None,
+            "dropping the Entered guard should exit the span"
+        );
+    }
+
+    #[test]
+    fn entered_guard_exits_span_on_panic() {
+        let current = CurrentSpanPerThread::new();
+        let span = Id::from_u64(21);
+
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            let _guard = current.enter_scoped(span.clone());
+            panic!("the guarded block panicked");
+        }));
+
+        assert!(result.is_err(), "the panic should propagate");
+        assert_eq!(
+            current.id(),
+            None,
+            "the guard should exit its span while unwinding from a panic"
+        );
+    }
 
     #[test]
     fn filters_are_not_reevaluated_for_the_same_span() {